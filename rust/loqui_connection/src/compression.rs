@@ -0,0 +1,121 @@
+use bytes::Bytes;
+use bytesize::ByteSize;
+use failure::Error;
+use std::io::Read;
+
+use crate::LoquiError;
+
+/// Compresses and decompresses frame payloads once a `compression` codec has
+/// been negotiated during the handshake. Mirrors `Encoder`, but operates on
+/// the raw bytes that come out of (or go into) an `Encoder`, rather than on
+/// the decoded value itself.
+pub trait Compression: Send + Sync {
+    fn compress(&self, payload: Bytes) -> Result<Bytes, Error>;
+
+    /// `max_payload_size` bounds the *inflated* size so a small compressed
+    /// frame can't be used to force the server to allocate an unbounded
+    /// amount of memory (a decompression bomb).
+    fn decompress(&self, payload: Bytes, max_payload_size: ByteSize) -> Result<Bytes, Error>;
+}
+
+/// Looks up a `Compression` implementation by the same name the factory
+/// advertises via `EncoderFactory::find_compression`.
+pub fn find_compression(name: &str) -> Option<Box<dyn Compression>> {
+    match name {
+        "gzip" => Some(Box::new(GzipCompression)),
+        "deflate" => Some(Box::new(DeflateCompression)),
+        "brotli" => Some(Box::new(BrotliCompression)),
+        "zstd" => Some(Box::new(ZstdCompression)),
+        _ => None,
+    }
+}
+
+/// Reads `reader` to completion, but bails out with
+/// `LoquiError::PayloadTooLarge` as soon as more than `max_payload_size`
+/// bytes have come out, instead of buffering an attacker-controlled amount
+/// of inflated data.
+fn read_bounded<R: Read>(mut reader: R, max_payload_size: ByteSize) -> Result<Bytes, Error> {
+    let limit = max_payload_size.as_bytes();
+    let mut buf = Vec::with_capacity(limit.min(8192) as usize);
+    let mut limited = reader.by_ref().take(limit + 1);
+    limited.read_to_end(&mut buf)?;
+    if buf.len() as u64 > limit {
+        return Err(LoquiError::PayloadTooLarge {
+            max_payload_size,
+            actual_payload_size: ByteSize::b(buf.len() as u64),
+        }
+        .into());
+    }
+    Ok(Bytes::from(buf))
+}
+
+pub struct GzipCompression;
+
+impl Compression for GzipCompression {
+    fn compress(&self, payload: Bytes) -> Result<Bytes, Error> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression as GzLevel;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+        encoder.write_all(&payload)?;
+        Ok(Bytes::from(encoder.finish()?))
+    }
+
+    fn decompress(&self, payload: Bytes, max_payload_size: ByteSize) -> Result<Bytes, Error> {
+        use flate2::read::GzDecoder;
+
+        read_bounded(GzDecoder::new(payload.as_ref()), max_payload_size)
+    }
+}
+
+pub struct DeflateCompression;
+
+impl Compression for DeflateCompression {
+    fn compress(&self, payload: Bytes) -> Result<Bytes, Error> {
+        use flate2::write::DeflateEncoder;
+        use flate2::Compression as DeflateLevel;
+        use std::io::Write;
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::default());
+        encoder.write_all(&payload)?;
+        Ok(Bytes::from(encoder.finish()?))
+    }
+
+    fn decompress(&self, payload: Bytes, max_payload_size: ByteSize) -> Result<Bytes, Error> {
+        use flate2::read::DeflateDecoder;
+
+        read_bounded(DeflateDecoder::new(payload.as_ref()), max_payload_size)
+    }
+}
+
+pub struct BrotliCompression;
+
+impl Compression for BrotliCompression {
+    fn compress(&self, payload: Bytes) -> Result<Bytes, Error> {
+        use brotli2::write::BrotliEncoder;
+        use std::io::Write;
+
+        let mut encoder = BrotliEncoder::new(Vec::new(), 5);
+        encoder.write_all(&payload)?;
+        Ok(Bytes::from(encoder.finish()?))
+    }
+
+    fn decompress(&self, payload: Bytes, max_payload_size: ByteSize) -> Result<Bytes, Error> {
+        use brotli2::read::BrotliDecoder;
+
+        read_bounded(BrotliDecoder::new(payload.as_ref()), max_payload_size)
+    }
+}
+
+pub struct ZstdCompression;
+
+impl Compression for ZstdCompression {
+    fn compress(&self, payload: Bytes) -> Result<Bytes, Error> {
+        Ok(Bytes::from(zstd::encode_all(payload.as_ref(), 0)?))
+    }
+
+    fn decompress(&self, payload: Bytes, max_payload_size: ByteSize) -> Result<Bytes, Error> {
+        read_bounded(zstd::Decoder::new(payload.as_ref())?, max_payload_size)
+    }
+}
@@ -1,27 +1,173 @@
-use crate::{Config, RequestHandler};
+use crate::{websocket, Config, RequestHandler};
+use bytes::{Bytes, BytesMut};
 use bytesize::ByteSize;
 use failure::Error;
+use futures::future::{abortable, AbortHandle, Aborted};
+use loqui_connection::compression::{find_compression, Compression};
 use loqui_connection::handler::{DelegatedFrame, Handler, Ready, TransportOptions};
 use loqui_connection::ReaderWriter;
 use loqui_connection::{Encoder, EncoderFactory, IdSequence, LoquiError};
 use loqui_protocol::frames::{Frame, Hello, HelloAck, LoquiFrame, Push, Request, Response};
 use loqui_protocol::upgrade::{Codec, UpgradeFrame};
 use loqui_protocol::VERSION;
+use std::collections::HashMap;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use tokio::await;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::timer::Timeout;
 use tokio_codec::Framed;
 
+/// Set on a frame's `flags` byte when its payload was compressed with the
+/// negotiated codec. Frames travel uncompressed (bit unset) when
+/// compression wasn't negotiated, or wasn't worth the bytes it would save.
+const FLAG_COMPRESSED: u8 = 0b0000_0001;
+
+/// Set on a `Request` frame when more frames carrying the rest of the same
+/// logical body will follow under the same `sequence_id`. The frame that
+/// finally completes the body leaves this bit unset, same as an ordinary
+/// single-frame request.
+const FLAG_FRAGMENTED: u8 = 0b0000_0010;
+
 pub struct ConnectionHandler<R: RequestHandler<F>, F: EncoderFactory> {
     config: Arc<Config<R, F>>,
+    compression: Arc<RwLock<Option<Arc<dyn Compression>>>>,
+    /// Bounds how many `Request`s this connection is decoding/handling at
+    /// once; a client that floods requests gets `TooManyInflightRequests`
+    /// instead of an unbounded pile of spawned tasks.
+    request_semaphore: Arc<Semaphore>,
+    /// Same idea for `Push`, which has no sequence id to fail back on, so
+    /// an exhausted permit just drops the push instead.
+    push_semaphore: Arc<Semaphore>,
+    /// Abort handles for the `handle_request` future of each in-flight
+    /// request, keyed by `sequence_id`. `HandleFrameFuture`s are returned to
+    /// the driver rather than spawned, so dropping them (which the driver
+    /// already does on teardown) cancels them on its own; `abort_handle`
+    /// only changes anything if the driver polls a returned future again
+    /// after dropping this handler, which makes that poll resolve to
+    /// `Aborted` immediately instead of doing another slice of real work.
+    /// See `cancel_all`.
+    cancel_handles: Arc<Mutex<HashMap<u32, AbortHandle>>>,
+    /// In-progress reassembly buffers for fragmented request bodies, keyed
+    /// by `sequence_id`, capped at `max_concurrent_reassembling_requests`
+    /// entries so a client can't pin unbounded memory by opening many
+    /// fragmented streams it never finishes. Pushes aren't fragmentable
+    /// since they carry no id to key a buffer on.
+    ///
+    /// Deliberately scoped to bounded whole-body reassembly, not streaming:
+    /// the body is fully reassembled and bounds-checked here before
+    /// `RequestHandler::handle_request` ever sees it, so a handler never
+    /// observes a partial body. A true streaming variant — handing
+    /// `RequestHandler` a `Stream` of chunks as they arrive, and the
+    /// matching fragmentation of oversized *responses* back to the client —
+    /// would need `RequestHandler` itself to grow a streaming entry point,
+    /// which is outside what this connection-handling module can add on its
+    /// own. That's a separate, larger change against the handler trait, not
+    /// something this reassembly path can backfill.
+    body_buffers: Arc<Mutex<HashMap<u32, BytesMut>>>,
 }
 
 impl<R: RequestHandler<F>, F: EncoderFactory> ConnectionHandler<R, F> {
     pub fn new(config: Arc<Config<R, F>>) -> Self {
-        Self { config }
+        let request_semaphore = Arc::new(Semaphore::new(config.max_concurrent_requests));
+        let push_semaphore = Arc::new(Semaphore::new(config.max_concurrent_pushes));
+        Self {
+            config,
+            compression: Arc::new(RwLock::new(None)),
+            request_semaphore,
+            push_semaphore,
+            cancel_handles: Arc::new(Mutex::new(HashMap::new())),
+            body_buffers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Accumulates a fragmented request body across multiple `Request`
+    /// frames sharing a `sequence_id`. Returns `Some(request)` with the
+    /// full reassembled payload once the final, non-fragmented frame
+    /// arrives; returns `None` while more fragments are still expected, or
+    /// after dropping a body that exceeded `max_total_body_size`.
+    fn reassemble_request(&self, request: Request) -> Option<Request> {
+        let Request {
+            sequence_id,
+            flags,
+            payload,
+        } = request;
+
+        let mut body_buffers = self.body_buffers.lock().expect("body_buffers lock poisoned");
+        if flags & FLAG_FRAGMENTED == 0 {
+            let payload = match body_buffers.remove(&sequence_id) {
+                Some(mut buffered) => {
+                    if (buffered.len() + payload.len()) as u64
+                        > self.config.max_total_body_size.as_bytes()
+                    {
+                        error!(
+                            "Dropping request {}: reassembled body exceeds max_total_body_size",
+                            sequence_id
+                        );
+                        return None;
+                    }
+                    buffered.extend_from_slice(&payload);
+                    buffered.freeze()
+                }
+                None => payload,
+            };
+            return Some(Request {
+                sequence_id,
+                flags,
+                payload,
+            });
+        }
+
+        if !body_buffers.contains_key(&sequence_id)
+            && body_buffers.len() >= self.config.max_concurrent_reassembling_requests
+        {
+            error!(
+                "Dropping request {}: max_concurrent_reassembling_requests exceeded",
+                sequence_id
+            );
+            return None;
+        }
+
+        let buffered = body_buffers.entry(sequence_id).or_insert_with(BytesMut::new);
+        buffered.extend_from_slice(&payload);
+        if buffered.len() as u64 > self.config.max_total_body_size.as_bytes() {
+            error!(
+                "Dropping request {}: reassembled body exceeds max_total_body_size",
+                sequence_id
+            );
+            body_buffers.remove(&sequence_id);
+        }
+        None
+    }
+
+    /// Marks every in-flight request's handler future as aborted. Called
+    /// from `Drop` on the chance the driver still has a returned
+    /// `HandleFrameFuture` around and polls it again after dropping this
+    /// handler — in that case the abort turns what would otherwise be
+    /// another real step of work into an immediate `Aborted`. It does
+    /// *not* provide cancellation on its own: a `HandleFrameFuture` the
+    /// driver simply drops without polling is already cancelled by that
+    /// drop, abort or no abort. Whether disconnect teardown actually hits
+    /// the former case depends on the driver's poll/drop ordering, which
+    /// lives outside this module.
+    fn cancel_all(&self) {
+        for (_, abort_handle) in self
+            .cancel_handles
+            .lock()
+            .expect("cancel_handles lock poisoned")
+            .drain()
+        {
+            abort_handle.abort();
+        }
+    }
+}
+
+impl<R: RequestHandler<F>, F: EncoderFactory> Drop for ConnectionHandler<R, F> {
+    fn drop(&mut self) {
+        self.cancel_all();
     }
 }
 
@@ -43,6 +189,17 @@ impl<R: RequestHandler<F>, F: EncoderFactory> Handler<F> for ConnectionHandler<R
     fn upgrade(&self, tcp_stream: TcpStream) -> Self::UpgradeFuture {
         let max_payload_size = self.max_payload_size();
         async move {
+            // Browsers and HTTP-only proxies can't send the native
+            // `UpgradeFrame::Request` handshake, so detect an HTTP
+            // WebSocket upgrade on the socket and complete the RFC6455
+            // handshake for it before falling into the native path below.
+            if await!(websocket::is_websocket_upgrade(&tcp_stream))? {
+                return Ok(await!(websocket::complete_handshake(
+                    tcp_stream,
+                    max_payload_size
+                ))?);
+            }
+
             let framed_socket = Framed::new(tcp_stream, Codec::new(max_payload_size));
             let (mut writer, mut reader) = framed_socket.split();
 
@@ -63,10 +220,15 @@ impl<R: RequestHandler<F>, F: EncoderFactory> Handler<F> for ConnectionHandler<R
 
     fn handshake(&mut self, mut reader_writer: ReaderWriter) -> Self::HandshakeFuture {
         let ping_interval = self.config.ping_interval;
+        let compression_slot = self.compression.clone();
+        let config = self.config.clone();
         async move {
             match await!(reader_writer.reader.next()) {
-                Some(Ok(frame)) => match Self::handle_handshake_frame(frame, ping_interval) {
-                    Ok((ready, hello_ack)) => {
+                Some(Ok(frame)) => match Self::handle_handshake_frame(frame, ping_interval, &config)
+                {
+                    Ok((ready, hello_ack, compression)) => {
+                        *compression_slot.write().expect("compression lock poisoned") =
+                            compression;
                         reader_writer = match await!(reader_writer.write(hello_ack)) {
                             Ok(reader_writer) => reader_writer,
                             Err(e) => return Err((e.into(), None)),
@@ -86,13 +248,58 @@ impl<R: RequestHandler<F>, F: EncoderFactory> Handler<F> for ConnectionHandler<R
         frame: DelegatedFrame,
         encoder: Arc<Box<dyn Encoder<Encoded = F::Encoded, Decoded = F::Decoded> + 'static>>,
     ) -> Option<Self::HandleFrameFuture> {
+        let compression = self
+            .compression
+            .read()
+            .expect("compression lock poisoned")
+            .clone();
+        let max_payload_size = self.max_payload_size();
         match frame {
             DelegatedFrame::Push(push) => {
-                tokio::spawn_async(handle_push(self.config.clone(), push, encoder));
+                match self.push_semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => {
+                        tokio::spawn_async(handle_push(
+                            self.config.clone(),
+                            push,
+                            encoder,
+                            compression,
+                            max_payload_size,
+                            permit,
+                        ));
+                    }
+                    Err(_) => {
+                        warn!("Dropping push: max_concurrent_pushes exceeded");
+                    }
+                }
                 None
             }
             DelegatedFrame::Request(request) => {
-                let response_future = handle_request(self.config.clone(), request, encoder);
+                let request = self.reassemble_request(request)?;
+                let sequence_id = request.sequence_id;
+                let (abortable_handler, abort_handle) = abortable(handle_request(
+                    self.config.clone(),
+                    request,
+                    encoder,
+                    compression,
+                    max_payload_size,
+                    self.request_semaphore.clone(),
+                ));
+                self.cancel_handles
+                    .lock()
+                    .expect("cancel_handles lock poisoned")
+                    .insert(sequence_id, abort_handle);
+                let cancel_handles = self.cancel_handles.clone();
+                let response_future = async move {
+                    let result = match await!(abortable_handler) {
+                        Ok(result) => result,
+                        Err(Aborted) => Err((LoquiError::RequestCancelled.into(), sequence_id)),
+                    };
+                    cancel_handles
+                        .lock()
+                        .expect("cancel_handles lock poisoned")
+                        .remove(&sequence_id);
+                    result
+                };
                 Some(response_future)
             }
             DelegatedFrame::Error(_) => None,
@@ -112,15 +319,57 @@ impl<R: RequestHandler<F>, F: EncoderFactory> Handler<F> for ConnectionHandler<R
     fn handle_ping(&mut self) {}
 }
 
+/// Decompresses `payload` if the sender's `flags` marked it as compressed.
+fn decompress_if_needed(
+    payload: Bytes,
+    flags: u8,
+    compression: Option<&Arc<dyn Compression>>,
+    max_payload_size: ByteSize,
+) -> Result<Bytes, Error> {
+    if flags & FLAG_COMPRESSED == 0 {
+        return Ok(payload);
+    }
+    let compression = compression.ok_or(LoquiError::NoCommonCompression)?;
+    compression.decompress(payload, max_payload_size)
+}
+
+/// Compresses `payload` with the negotiated codec, but only if doing so
+/// actually shrinks it; otherwise the payload is sent as-is so small
+/// responses don't pay for a compression header that doesn't earn its keep.
+fn compress_if_worthwhile(
+    payload: Bytes,
+    compression: Option<&Arc<dyn Compression>>,
+) -> Result<(Bytes, u8), Error> {
+    match compression {
+        Some(compression) => {
+            let compressed = compression.compress(payload.clone())?;
+            if compressed.len() < payload.len() {
+                Ok((compressed, FLAG_COMPRESSED))
+            } else {
+                Ok((payload, 0))
+            }
+        }
+        None => Ok((payload, 0)),
+    }
+}
+
 async fn handle_push<F: EncoderFactory, R: RequestHandler<F>>(
     config: Arc<Config<R, F>>,
     push: Push,
     encoder: Arc<Box<dyn Encoder<Encoded = F::Encoded, Decoded = F::Decoded> + 'static>>,
+    compression: Option<Arc<dyn Compression>>,
+    max_payload_size: ByteSize,
+    _permit: OwnedSemaphorePermit,
 ) {
-    let Push {
-        payload,
-        flags: _flags,
-    } = push;
+    let Push { payload, flags } = push;
+    let payload =
+        match decompress_if_needed(payload, flags, compression.as_ref(), max_payload_size) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to decompress push payload. error={:?}", e);
+                return;
+            }
+        };
     match encoder.decode(payload) {
         Ok(request) => {
             config.request_handler.handle_push(request);
@@ -135,19 +384,40 @@ async fn handle_request<F: EncoderFactory, R: RequestHandler<F>>(
     config: Arc<Config<R, F>>,
     request: Request,
     encoder: Arc<Box<dyn Encoder<Encoded = F::Encoded, Decoded = F::Decoded> + 'static>>,
+    compression: Option<Arc<dyn Compression>>,
+    max_payload_size: ByteSize,
+    request_semaphore: Arc<Semaphore>,
 ) -> Result<Response, (Error, u32)> {
     let Request {
         payload,
-        flags: _flags,
+        flags,
         sequence_id,
     } = request;
+    let _permit = request_semaphore
+        .try_acquire_owned()
+        .map_err(|_| (LoquiError::TooManyInflightRequests.into(), sequence_id))?;
+    let payload = decompress_if_needed(payload, flags, compression.as_ref(), max_payload_size)
+        .map_err(|e| (e, sequence_id))?;
     let request = encoder.decode(payload).map_err(|e| (e, sequence_id))?;
 
-    let response = await!(config.request_handler.handle_request(request));
+    let response = match config.request_timeout {
+        Some(request_timeout) => {
+            match await!(Timeout::new(
+                config.request_handler.handle_request(request),
+                request_timeout
+            )) {
+                Ok(response) => response,
+                Err(_elapsed) => return Err((LoquiError::RequestTimeout.into(), sequence_id)),
+            }
+        }
+        None => await!(config.request_handler.handle_request(request)),
+    };
 
     let payload = encoder.encode(response).map_err(|e| (e, sequence_id))?;
+    let (payload, flags) =
+        compress_if_worthwhile(payload, compression.as_ref()).map_err(|e| (e, sequence_id))?;
     Ok(Response {
-        flags: 0,
+        flags,
         sequence_id,
         payload,
     })
@@ -157,9 +427,10 @@ impl<F: EncoderFactory, R: RequestHandler<F>> ConnectionHandler<R, F> {
     fn handle_handshake_frame(
         frame: LoquiFrame,
         ping_interval: Duration,
-    ) -> Result<(Ready, HelloAck), Error> {
+        config: &Config<R, F>,
+    ) -> Result<(Ready, HelloAck, Option<Arc<dyn Compression>>), Error> {
         match frame {
-            LoquiFrame::Hello(hello) => Self::handle_handshake_hello(hello, ping_interval),
+            LoquiFrame::Hello(hello) => Self::handle_handshake_hello(hello, ping_interval, config),
             LoquiFrame::GoAway(go_away) => Err(LoquiError::ToldToGoAway { go_away }.into()),
             frame => Err(LoquiError::InvalidOpcode {
                 actual: frame.opcode(),
@@ -172,7 +443,8 @@ impl<F: EncoderFactory, R: RequestHandler<F>> ConnectionHandler<R, F> {
     fn handle_handshake_hello(
         hello: Hello,
         ping_interval: Duration,
-    ) -> Result<(Ready, HelloAck), Error> {
+        config: &Config<R, F>,
+    ) -> Result<(Ready, HelloAck, Option<Arc<dyn Compression>>), Error> {
         let Hello {
             flags,
             version,
@@ -186,45 +458,85 @@ impl<F: EncoderFactory, R: RequestHandler<F>> ConnectionHandler<R, F> {
             }
             .into());
         }
-        let encoding = Self::negotiate_encoding(&encodings)?;
-        let compression = Self::negotiate_compression(&compressions)?;
+        let encoding = Self::negotiate_encoding(&encodings, &config.encoding_preferences)?;
+        let compression_name =
+            Self::negotiate_compression(&compressions, &config.compression_preferences)?;
+        // `F::find_compression` (what negotiation matched against) and this
+        // module's `find_compression` (what can actually codec the bytes)
+        // are independent registries; a factory can legally advertise a name
+        // this module has no codec for, so treat that as a negotiation
+        // failure rather than trusting the cross-module invariant to hold.
+        let compression: Option<Arc<dyn Compression>> = match compression_name {
+            Some(name) => Some(Arc::from(
+                find_compression(name).ok_or(LoquiError::NoCommonCompression)?,
+            )),
+            None => None,
+        };
         let hello_ack = HelloAck {
             flags,
             ping_interval_ms: ping_interval.as_millis() as u32,
             encoding: encoding.to_string(),
-            compression: compression.map(String::from),
+            compression: compression_name.map(String::from),
         };
         let ready = Ready {
             ping_interval,
             transport_options: TransportOptions {
                 encoding,
-                compression,
+                compression: compression_name,
             },
         };
-        Ok((ready, hello_ack))
+        Ok((ready, hello_ack, compression))
     }
 
-    fn negotiate_encoding(client_encodings: &[String]) -> Result<&'static str, Error> {
-        for client_encoding in client_encodings {
-            if let Some(encoding) = F::find_encoding(client_encoding) {
-                return Ok(encoding);
+    /// Picks the mutually-supported codec the server prefers most, rather
+    /// than the first one the client happens to list. This mirrors HTTP
+    /// `Accept-Encoding` q-value negotiation: `preferences` assigns each
+    /// codec name a server-side weight (higher wins), and the client's own
+    /// ordering only breaks ties between equally-weighted codecs.
+    fn negotiate(
+        client_order: &[String],
+        preferences: &[(String, u32)],
+        find: impl Fn(&str) -> Option<&'static str>,
+    ) -> Option<&'static str> {
+        let mut best: Option<(&'static str, u32)> = None;
+        for client_name in client_order {
+            let resolved = match find(client_name) {
+                Some(resolved) => resolved,
+                None => continue,
+            };
+            // Weight by the resolved canonical name, not the client's raw
+            // string: a client may send an alias `find` still resolves, and
+            // operators configure `preferences` in terms of the canonical
+            // names the factory advertises.
+            let weight = preferences
+                .iter()
+                .find(|(name, _)| name == resolved)
+                .map_or(0, |(_, weight)| *weight);
+            if best.map_or(true, |(_, best_weight)| weight > best_weight) {
+                best = Some((resolved, weight));
             }
         }
-        Err(LoquiError::NoCommonEncoding.into())
+        best.map(|(resolved, _)| resolved)
+    }
+
+    fn negotiate_encoding(
+        client_encodings: &[String],
+        preferences: &[(String, u32)],
+    ) -> Result<&'static str, Error> {
+        Self::negotiate(client_encodings, preferences, F::find_encoding)
+            .ok_or_else(|| LoquiError::NoCommonEncoding.into())
     }
 
     fn negotiate_compression(
         client_compressions: &[String],
+        preferences: &[(String, u32)],
     ) -> Result<Option<&'static str>, Error> {
         if client_compressions.is_empty() {
             return Ok(None);
         }
 
-        for client_compression in client_compressions {
-            if let Some(compression) = F::find_compression(client_compression) {
-                return Ok(Some(compression));
-            }
-        }
-        Err(LoquiError::NoCommonCompression.into())
+        Self::negotiate(client_compressions, preferences, F::find_compression)
+            .map(Some)
+            .ok_or_else(|| LoquiError::NoCommonCompression.into())
     }
 }
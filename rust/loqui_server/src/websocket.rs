@@ -0,0 +1,377 @@
+use base64::encode;
+use bytesize::ByteSize;
+use failure::Error;
+use futures::channel::mpsc;
+use futures::future::Either;
+use sha1::Sha1;
+use std::io;
+use tokio::await;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+/// RFC6455 section 1.3: every `Sec-WebSocket-Accept` is derived from the
+/// client's key concatenated with this fixed GUID.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// A connection opens with either the native `UpgradeFrame::Request` or, if
+/// it's a browser/proxy that can only speak HTTP, a WebSocket upgrade
+/// request. Peeking the first bytes without consuming them lets `upgrade`
+/// pick the right handshake before handing the socket to either path.
+pub async fn is_websocket_upgrade(tcp_stream: &TcpStream) -> Result<bool, Error> {
+    let mut buf = [0u8; 3];
+    let n = await!(peek(tcp_stream, &mut buf))?;
+    Ok(&buf[..n] == b"GET")
+}
+
+/// Completes the RFC6455 opening handshake on `tcp_stream`, then bridges it
+/// to a local loopback `TcpStream` that carries the same bytes the native
+/// `UpgradeFrame` path would hand `upgrade()`: plain, unwrapped loqui frame
+/// bytes. A background task does the actual WebSocket data framing (masking
+/// inbound client frames off, wrapping outbound bytes in binary frames) so
+/// `handshake`/`handle_frame` never have to know the client is a browser.
+pub async fn complete_handshake(
+    mut tcp_stream: TcpStream,
+    max_payload_size: ByteSize,
+) -> Result<TcpStream, Error> {
+    let (request, rest) = await!(read_http_request(&mut tcp_stream))?;
+    validate_upgrade_request(&request)?;
+    let key = header_value(&request, "sec-websocket-key")
+        .ok_or_else(|| WebSocketError::MissingSecWebSocketKey)?;
+    let accept = accept_key(&key);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    await!(tcp_stream.write_all(response.as_bytes()))?;
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse()?)?;
+    let local_addr = listener.local_addr()?;
+    let accept_loopback = listener.incoming().into_future();
+    let connect_loopback = TcpStream::connect(&local_addr);
+    let ((loopback_server, _), loopback_client) =
+        await!(accept_loopback.join(connect_loopback)).map_err(|(e, _)| e)?;
+    let loopback_server = loopback_server.ok_or(LoquiWebSocketBridgeError::LoopbackClosed)?;
+
+    tokio::spawn_async(bridge(
+        tcp_stream,
+        loopback_client,
+        rest,
+        max_payload_size,
+    ));
+    Ok(loopback_server)
+}
+
+fn accept_key(sec_websocket_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(sec_websocket_key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    encode(&hasher.digest().bytes())
+}
+
+fn validate_upgrade_request(request: &str) -> Result<(), Error> {
+    let has_upgrade = header_value(request, "upgrade")
+        .map_or(false, |value| value.eq_ignore_ascii_case("websocket"));
+    let has_connection_upgrade = header_value(request, "connection")
+        .map_or(false, |value| value.to_ascii_lowercase().contains("upgrade"));
+    let version_13 = header_value(request, "sec-websocket-version").as_deref() == Some("13");
+    if has_upgrade && has_connection_upgrade && version_13 {
+        Ok(())
+    } else {
+        Err(WebSocketError::InvalidUpgradeRequest.into())
+    }
+}
+
+fn header_value(request: &str, header_name: &str) -> Option<String> {
+    request.lines().skip(1).find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if name.eq_ignore_ascii_case(header_name) {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Reads off `tcp_stream` until the `\r\n\r\n` request terminator, returning
+/// the header text and any bytes read past the terminator in the same
+/// chunk (the start of the first WebSocket frame a pipelining client sent
+/// right after the upgrade request) so they aren't silently dropped.
+async fn read_http_request(tcp_stream: &mut TcpStream) -> Result<(String, Vec<u8>), Error> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        if let Some(terminator_end) = find_header_terminator(&buf) {
+            let rest = buf.split_off(terminator_end);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), rest));
+        }
+        let n = await!(tcp_stream.read(&mut chunk))?;
+        if n == 0 {
+            return Err(WebSocketError::IncompleteUpgradeRequest.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn find_header_terminator(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|start| start + 4)
+}
+
+async fn peek(tcp_stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    await!(future::poll_fn(|| tcp_stream.poll_peek(buf)))
+}
+
+/// Pumps bytes between the real client-facing WebSocket socket and the
+/// loopback socket `upgrade()` handed off to the native frame-handling
+/// path, applying RFC6455 framing in both directions and unmasking the
+/// client's frames (frames sent to the client go out unmasked).
+async fn bridge(
+    ws_socket: TcpStream,
+    loopback: TcpStream,
+    leftover: Vec<u8>,
+    max_payload_size: ByteSize,
+) {
+    let (ws_reader, ws_writer) = ws_socket.split();
+    let (loopback_reader, loopback_writer) = loopback.split();
+    let ws_reader = PrefixedReader::new(leftover, ws_reader);
+
+    // Pongs are written to `ws_writer` by the same task that writes the
+    // loopback's outbound bytes, since only one side can hold the writer
+    // at a time; `ws_to_loopback` hands them off here instead.
+    let (pong_tx, pong_rx) = mpsc::channel(8);
+
+    let ws_to_loopback = ws_to_loopback(ws_reader, loopback_writer, pong_tx, max_payload_size);
+    let loopback_to_ws = loopback_to_ws(loopback_reader, ws_writer, pong_rx);
+    await!(future::join(ws_to_loopback, loopback_to_ws));
+}
+
+/// A reader that drains `prefix` (the pipelined bytes a client sent right
+/// after the upgrade request, already pulled off the socket while parsing
+/// its HTTP headers) before falling through to `inner`. This lets
+/// `read_frame` parse frames the same way regardless of whether their
+/// bytes arrived before or after the bridge took over the socket,
+/// including a frame that was only partially buffered when the prefix
+/// runs out.
+struct PrefixedReader<R> {
+    prefix: Vec<u8>,
+    inner: R,
+}
+
+impl<R: AsyncRead> PrefixedReader<R> {
+    fn new(prefix: Vec<u8>, inner: R) -> Self {
+        Self { prefix, inner }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.prefix.is_empty() {
+            let n = buf.len().min(self.prefix.len());
+            buf[..n].copy_from_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Ok(n);
+        }
+        await!(self.inner.read(buf))
+    }
+}
+
+async fn ws_to_loopback<R, W>(
+    mut ws_reader: PrefixedReader<R>,
+    mut loopback_writer: W,
+    mut pong_tx: mpsc::Sender<Vec<u8>>,
+    max_payload_size: ByteSize,
+)
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    loop {
+        match await!(read_frame(&mut ws_reader, max_payload_size)) {
+            Ok(Some(frame)) => match frame.opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if await!(loopback_writer.write_all(&frame.payload)).is_err() {
+                        return;
+                    }
+                }
+                OPCODE_CLOSE => return,
+                OPCODE_PING => {
+                    // RFC6455 section 5.5.2: a PONG sent in response to a
+                    // PING MUST carry the same payload. `loopback_to_ws`
+                    // owns the writer, so hand it off instead of writing
+                    // here.
+                    if await!(pong_tx.send(frame.payload)).is_err() {
+                        return;
+                    }
+                }
+                OPCODE_PONG => {}
+                _ => {}
+            },
+            _ => return,
+        }
+    }
+}
+
+async fn loopback_to_ws<R, W>(mut loopback_reader: R, mut ws_writer: W, mut pong_rx: mpsc::Receiver<Vec<u8>>)
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    let mut buf = [0u8; 8192];
+    loop {
+        match await!(future::select(loopback_reader.read(&mut buf), pong_rx.next())) {
+            Either::Left((Ok(0), _)) => return,
+            Either::Left((Ok(n), _)) => {
+                if await!(write_frame(&mut ws_writer, OPCODE_BINARY, &buf[..n])).is_err() {
+                    return;
+                }
+            }
+            Either::Left((Err(_), _)) => return,
+            Either::Right((Some(payload), _)) => {
+                if await!(write_frame(&mut ws_writer, OPCODE_PONG, &payload)).is_err() {
+                    return;
+                }
+            }
+            // The sender side closed along with `ws_to_loopback`; nothing
+            // left to reply to, but loopback reads may still be in flight.
+            Either::Right((None, _)) => {}
+        }
+    }
+}
+
+struct WsFrame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+async fn read_frame<R: AsyncRead>(
+    reader: &mut PrefixedReader<R>,
+    max_payload_size: ByteSize,
+) -> Result<Option<WsFrame>, Error> {
+    let mut header = [0u8; 2];
+    if !await!(read_exact_or_eof(reader, &mut header))? {
+        return Ok(None);
+    }
+    let opcode = header[0] & 0x0F;
+    let masked = header[1] & 0x80 != 0;
+    let mut len = u64::from(header[1] & 0x7F);
+    if len == 126 {
+        let mut ext = [0u8; 2];
+        await!(read_exact(reader, &mut ext))?;
+        len = u64::from(u16::from_be_bytes(ext));
+    } else if len == 127 {
+        let mut ext = [0u8; 8];
+        await!(read_exact(reader, &mut ext))?;
+        len = u64::from_be_bytes(ext);
+    }
+    let mask = if masked {
+        let mut mask = [0u8; 4];
+        await!(read_exact(reader, &mut mask))?;
+        Some(mask)
+    } else {
+        None
+    };
+    if len > max_payload_size.as_bytes() {
+        return Err(WebSocketError::FrameTooLarge {
+            max_payload_size,
+            actual_payload_size: ByteSize::b(len),
+        }
+        .into());
+    }
+    let mut payload = vec![0u8; len as usize];
+    await!(read_exact(reader, &mut payload))?;
+    if let Some(mask) = mask {
+        // RFC6455 section 5.3: every frame a client sends MUST be masked;
+        // the server MUST unmask it by XOR-ing with the 4-byte masking key.
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Some(WsFrame { opcode, payload }))
+}
+
+/// Server-to-client frames are sent unmasked, as RFC6455 requires.
+async fn write_frame<W: AsyncWrite>(writer: &mut W, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut header = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        header.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        header.push(126);
+        header.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    await!(writer.write_all(&header))?;
+    await!(writer.write_all(payload))?;
+    Ok(())
+}
+
+/// Like `read_exact`, but returns `Ok(false)` instead of erroring when the
+/// peer closes before any bytes of a new frame arrive.
+async fn read_exact_or_eof<R: AsyncRead>(
+    reader: &mut PrefixedReader<R>,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = await!(reader.read(&mut buf[filled..]))?;
+        if n == 0 {
+            return if filled == 0 {
+                Ok(false)
+            } else {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+            };
+        }
+        filled += n;
+    }
+    Ok(true)
+}
+
+/// Reads exactly `buf.len()` bytes, erroring on EOF regardless of how many
+/// bytes were already filled — unlike `read_exact_or_eof`, every call site
+/// here is mid-frame, where a closed connection is always unexpected.
+async fn read_exact<R: AsyncRead>(
+    reader: &mut PrefixedReader<R>,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    if await!(read_exact_or_eof(reader, buf))? {
+        Ok(())
+    } else {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+#[derive(Debug, Fail)]
+enum WebSocketError {
+    #[fail(display = "WebSocket upgrade request is missing Sec-WebSocket-Key")]
+    MissingSecWebSocketKey,
+    #[fail(display = "WebSocket upgrade request is missing or has invalid Upgrade/Connection/Sec-WebSocket-Version headers")]
+    InvalidUpgradeRequest,
+    #[fail(display = "Connection closed before the WebSocket upgrade request completed")]
+    IncompleteUpgradeRequest,
+    #[fail(
+        display = "WebSocket frame claims a {} payload, which exceeds the {} max_payload_size",
+        actual_payload_size, max_payload_size
+    )]
+    FrameTooLarge {
+        max_payload_size: ByteSize,
+        actual_payload_size: ByteSize,
+    },
+}
+
+#[derive(Debug, Fail)]
+enum LoquiWebSocketBridgeError {
+    #[fail(display = "Loopback listener closed before accepting the bridge connection")]
+    LoopbackClosed,
+}